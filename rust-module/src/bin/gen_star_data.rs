@@ -1,10 +1,7 @@
 use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use rust_module::{
-    star::{Star, System},
-    trie::CompactNode,
-};
+use rust_module::star::{Star, System};
 
 use rust_module::{
     star::{Partition, reorder_for_partitions},
@@ -17,7 +14,7 @@ fn analyze() -> io::Result<()> {
     let mut buf = vec![];
     file.read_to_end(&mut buf)?;
     let trie = CompactRadixTrie::from_bytes(&buf);
-    println!("Trie has {} nodes", trie.nodes.len());
+    println!("Trie has {} nodes", trie.node_count());
 
     // dbg!(trie.suggest("Speamo", 10));
 
@@ -25,8 +22,11 @@ fn analyze() -> io::Result<()> {
 }
 
 fn main() -> io::Result<()> {
-    // analyze()?;
-    // return Ok(());
+    // `--analyze` inspects the previously generated trie instead of regenerating the data
+    if std::env::args().any(|arg| arg == "--analyze") {
+        return analyze();
+    }
+
     let mut file = std::fs::File::open("systems_neutron.json")?;
     file.seek(SeekFrom::Start(1))?;
     let reader = BufReader::new(file);
@@ -77,7 +77,7 @@ fn main() -> io::Result<()> {
     let chunk_size = stars.len() / num_partitions;
     let partitions: Vec<Partition> = stars
         .chunks(chunk_size)
-        .map(|p| Partition::new(p))
+        .map(Partition::new)
         .collect();
 
     println!(
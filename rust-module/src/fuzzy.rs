@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+/// State of a `FuzzyMatcher` from which no match can be reached anymore
+const DEAD: u32 = 0;
+
+/// A deterministic Levenshtein automaton: it accepts every byte string within
+/// `max_distance` edits (insertions, deletions, substitutions) of a query.
+///
+/// Building the automaton is the expensive part of a fuzzy search, running it
+/// is a table lookup per byte. Build one matcher per query and reuse it for
+/// as many searches (and tries) as needed.
+///
+/// Each state stands for one row of the edit distance table, with distances
+/// above `max_distance` folded together. Only the states reachable from the
+/// start are built. Distances are counted in bytes, so a non-ASCII character
+/// counts as more than one edit.
+#[derive(Debug, Clone)]
+pub struct FuzzyMatcher {
+    max_distance: usize,
+    /// Column in `transitions` for each byte. All bytes that don't occur in
+    /// the query behave the same and share the last column.
+    byte_class: [u16; 256],
+    num_classes: usize,
+    /// `transitions[state * num_classes + class]` is the next state
+    transitions: Vec<u32>,
+    /// Distance between the bytes read so far and the whole query,
+    /// `u8::MAX` where it is above `max_distance`
+    distances: Vec<u8>,
+}
+
+impl FuzzyMatcher {
+    /// Builds the automaton for `query`. `max_distance` is capped at 254.
+    pub fn new(query: &str, max_distance: usize) -> Self {
+        let query = query.as_bytes();
+        let max_distance = max_distance.min(u8::MAX as usize - 1);
+        // Every distance above max_distance is stored as `limit`
+        let limit = max_distance as u8 + 1;
+
+        let mut byte_class = [u16::MAX; 256];
+        let mut class_bytes = Vec::new();
+        for &b in query {
+            if byte_class[b as usize] == u16::MAX {
+                byte_class[b as usize] = class_bytes.len() as u16;
+                class_bytes.push(Some(b));
+            }
+        }
+        let other_class = class_bytes.len() as u16;
+        for class in byte_class.iter_mut() {
+            if *class == u16::MAX {
+                *class = other_class;
+            }
+        }
+        class_bytes.push(None);
+        let num_classes = class_bytes.len();
+
+        let dead_row = vec![limit; query.len() + 1];
+        let start_row: Vec<u8> = (0..=query.len())
+            .map(|i| i.min(limit as usize) as u8)
+            .collect();
+
+        let mut rows = vec![dead_row.clone(), start_row.clone()];
+        let mut ids: HashMap<Vec<u8>, u32> = HashMap::new();
+        ids.insert(dead_row, DEAD);
+        ids.insert(start_row, 1);
+
+        // Breadth-first over the reachable rows, `rows` doubles as the queue
+        let mut transitions = Vec::new();
+        let mut state = 0;
+        while state < rows.len() {
+            for &byte in &class_bytes {
+                let next_row = Self::step_row(query, &rows[state], byte, limit);
+                let next_id = match ids.get(&next_row) {
+                    Some(&id) => id,
+                    None => {
+                        let id = rows.len() as u32;
+                        ids.insert(next_row.clone(), id);
+                        rows.push(next_row);
+                        id
+                    }
+                };
+                transitions.push(next_id);
+            }
+            state += 1;
+        }
+
+        let distances = rows
+            .iter()
+            .map(|row| {
+                let distance = row[query.len()];
+                if distance < limit { distance } else { u8::MAX }
+            })
+            .collect();
+
+        Self {
+            max_distance,
+            byte_class,
+            num_classes,
+            transitions,
+            distances,
+        }
+    }
+
+    /// One row of the edit distance table from the previous one.
+    /// `byte` is `None` for bytes that don't occur in the query.
+    fn step_row(query: &[u8], row: &[u8], byte: Option<u8>, limit: u8) -> Vec<u8> {
+        let mut next = Vec::with_capacity(row.len());
+        next.push(row[0].saturating_add(1).min(limit));
+        for i in 1..row.len() {
+            let substitution = row[i - 1].saturating_add((Some(query[i - 1]) != byte) as u8);
+            let deletion = row[i].saturating_add(1);
+            let insertion = next[i - 1].saturating_add(1);
+            next.push(substitution.min(deletion).min(insertion).min(limit));
+        }
+        next
+    }
+
+    pub fn max_distance(&self) -> usize {
+        self.max_distance
+    }
+
+    /// Number of states in the automaton, including the dead state.
+    pub fn state_count(&self) -> usize {
+        self.distances.len()
+    }
+
+    /// The state before any input has been read.
+    pub fn start(&self) -> u32 {
+        1
+    }
+
+    /// The state after reading `byte` in `state`.
+    pub fn step(&self, state: u32, byte: u8) -> u32 {
+        let class = self.byte_class[byte as usize] as usize;
+        self.transitions[state as usize * self.num_classes + class]
+    }
+
+    /// Whether no continuation of the input can match anymore.
+    pub fn is_dead(&self, state: u32) -> bool {
+        state == DEAD
+    }
+
+    /// Edit distance between the input read so far and the query,
+    /// or `None` if it is more than `max_distance`.
+    pub fn distance(&self, state: u32) -> Option<usize> {
+        match self.distances[state as usize] {
+            u8::MAX => None,
+            distance => Some(distance as usize),
+        }
+    }
+
+    /// Runs the automaton over `input`, returning its distance to the
+    /// query if it is within `max_distance`.
+    pub fn matches(&self, input: &[u8]) -> Option<usize> {
+        let mut state = self.start();
+        for &b in input {
+            state = self.step(state, b);
+            if self.is_dead(state) {
+                return None;
+            }
+        }
+        self.distance(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levenshtein(a: &[u8], b: &[u8]) -> usize {
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for i in 1..=a.len() {
+            let mut prev = row[0];
+            row[0] = i;
+            for j in 1..=b.len() {
+                let current = row[j];
+                row[j] = (prev + (a[i - 1] != b[j - 1]) as usize)
+                    .min(row[j] + 1)
+                    .min(row[j - 1] + 1);
+                prev = current;
+            }
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn test_matches_agree_with_levenshtein() {
+        let queries = ["", "a", "kitten", "sitting", "abcabc", "neutron"];
+        let inputs = [
+            "", "a", "b", "kitten", "sitting", "kiten", "kittens", "mitten", "abc", "abcab",
+            "cbacba", "neutrino", "newtron", "xyz",
+        ];
+
+        for query in queries {
+            for max_distance in 0..=3 {
+                let matcher = FuzzyMatcher::new(query, max_distance);
+                for input in inputs {
+                    let distance = levenshtein(query.as_bytes(), input.as_bytes());
+                    let expected = (distance <= max_distance).then_some(distance);
+                    assert_eq!(
+                        matcher.matches(input.as_bytes()),
+                        expected,
+                        "query {:?}, input {:?}, max_distance {}",
+                        query,
+                        input,
+                        max_distance
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dead_state_is_absorbing() {
+        let matcher = FuzzyMatcher::new("abc", 1);
+        let mut state = matcher.start();
+        for &b in b"xyz" {
+            state = matcher.step(state, b);
+        }
+        assert!(matcher.is_dead(state));
+        assert!(matcher.is_dead(matcher.step(state, b'a')));
+        assert!(matcher.state_count() > 2);
+    }
+}
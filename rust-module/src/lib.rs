@@ -1,3 +1,4 @@
+pub mod fuzzy;
 pub mod star;
 pub mod trie;
 pub mod utils;
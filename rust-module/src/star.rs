@@ -90,7 +90,7 @@ impl<'a> Partition<'a> {
         let bytes = unsafe {
             std::slice::from_raw_parts(
                 self.stars.as_ptr() as *const u8,
-                self.stars.len() * std::mem::size_of::<Star>(),
+                std::mem::size_of_val(self.stars),
             )
         };
         writer.write_all(bytes).unwrap();